@@ -1,22 +1,46 @@
 
+use std::io;
 use std::io::BufReader;
 use std::io::BufRead;
+use std::io::Write;
 use std::fs::File;
 use std::env;
 use std::collections::HashMap;
+use std::process;
+
+const REG_ZERO: u32 = 0;
+const REG_AT: u32 = 1;
 
 #[derive(Debug, Clone, Copy)]
 enum Mnemonic {
     AND,
     OR,
+    NOR,
     J,
+    JAL,
+    JR,
     SLT,
     ADD,
     SUB,
     ADDI,
+    ANDI,
+    XORI,
     BEQ,
     SW,
     LW,
+    BNE,
+    LUI,
+    ORI,
+    SLL,
+    SRL,
+    SRA,
+    // pseudo-instructions, rewritten away by expand_pseudo() before encoding
+    MOVE,
+    NOP,
+    LI,
+    LA,
+    BLT,
+    BGT,
 }
 
 #[derive(Debug, Clone)]
@@ -24,6 +48,9 @@ enum Operand {
     Reg(u32),
     Im(i32),
     Label(String),
+    // upper/lower 16 bits of a label's address, produced while expanding `la`
+    LabelHi(String),
+    LabelLo(String),
 }
 
 #[derive(Clone)]
@@ -31,15 +58,106 @@ struct Instruction {
     label: Option<String>,
     mnemonic: Mnemonic,
     operands: Vec<Operand>,
+    line: usize,
+    text: String,
 }
 
-enum InstructionType {
-    R,
-    I,
-    J,
+// A line of source is either a real/pseudo instruction or an assembler
+// directive. Directives don't encode to an opcode themselves, but they do
+// occupy words and can carry a label, so they take part in address
+// resolution alongside instructions.
+enum Item {
+    Instr(Instruction),
+    Word { label: Option<String>, values: Vec<i32> },
+    Space { label: Option<String>, count: usize },
+    Org(u32),
+    Segment,
+}
+
+// A diagnostic tied to a source line, printed as `file:line: message` with
+// the offending text and a caret, in the spirit of rustc-style errors.
+struct AsmError {
+    line: usize,
+    column: usize,
+    text: String,
+    message: String,
+}
+
+fn asm_error(line: usize, text: &str, token: &str, message: String) -> AsmError {
+    AsmError {
+        line: line,
+        column: text.find(token).unwrap_or(0),
+        text: text.to_string(),
+        message: message,
+    }
+}
+
+fn report_errors(filename: &str, errors: &[AsmError]) {
+    for e in errors {
+        eprintln!("{}:{}: {}", filename, e.line, e.message);
+        eprintln!("    {}", e.text);
+        eprintln!("    {}^", " ".repeat(e.column));
+    }
 }
 
-fn str2instr(s: &String) -> Result<Instruction, String> {
+// Recognizes `.text`/`.data`/`.word`/`.space`/`.org`, falling back to
+// str2instr for everything else. Directives share the label-before-colon
+// syntax instructions use, e.g. `arr: .word 1, 2, 3`.
+fn str2item(s: &String, line: usize) -> Result<Item, AsmError> {
+    let text = s.trim_end_matches(|c| c == '\n' || c == '\r').to_string();
+
+    let split_colon: Vec<_> = s.trim().split(':').collect();
+    let (label, rest) = if split_colon.len() >= 2 {
+        (Some(split_colon[0].trim().to_string()), split_colon[1].trim())
+    } else {
+        (None, split_colon[0].trim())
+    };
+
+    if rest.starts_with(".word") {
+        let values = rest[".word".len()..]
+            .split(',')
+            .map(|v| v.trim())
+            .filter(|&v| v != "")
+            .map(|v| {
+                v.parse::<i32>().map_err(|_| {
+                    asm_error(line, &text, v, format!("expected immediate, found `{}`", v))
+                })
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        return Ok(Item::Word { label: label, values: values });
+    }
+    if rest.starts_with(".space") {
+        let n_str = rest[".space".len()..].trim();
+        let count = n_str.parse::<usize>().map_err(|_| {
+            asm_error(line, &text, n_str, format!("expected a word count, found `{}`", n_str))
+        })?;
+        return Ok(Item::Space { label: label, count: count });
+    }
+    if rest.starts_with(".org") {
+        let adr_str = rest[".org".len()..].trim();
+        let address = parse_address(adr_str).map_err(|_| {
+            asm_error(line, &text, adr_str, format!("expected an address, found `{}`", adr_str))
+        })?;
+        return Ok(Item::Org(address));
+    }
+    if rest == ".text" || rest == ".data" {
+        return Ok(Item::Segment);
+    }
+
+    str2instr(s, line).map(Item::Instr)
+}
+
+fn parse_address(s: &str) -> Result<u32, ()> {
+    if s.starts_with("0x") {
+        u32::from_str_radix(&s[2..], 16).map_err(|_| ())
+    } else {
+        s.parse::<u32>().map_err(|_| ())
+    }
+}
+
+fn str2instr(s: &String, line: usize) -> Result<Instruction, AsmError> {
+    let text = s.trim_end_matches(|c| c == '\n' || c == '\r').to_string();
+
     let split_colon: Vec<_> = s.trim().split(':').collect();
     let (label, operation_str) = if split_colon.len() >= 2 {
         (Some(split_colon[0].trim().to_string()), split_colon[1])
@@ -53,189 +171,1065 @@ fn str2instr(s: &String) -> Result<Instruction, String> {
     );
     let mut split_space: Vec<_> = operation_str.split(" ").filter(|&s| s != "").collect();
 
+    if split_space.is_empty() {
+        return Err(asm_error(line, &text, &text, "expected a mnemonic".to_string()));
+    }
     let operands_str: Vec<_> = split_space.split_off(1);
-    let mnemonic = match split_space[0] {
+    let mnemonic_str = split_space[0];
+    let mnemonic = match mnemonic_str {
         "and" => Mnemonic::AND,
         "or" => Mnemonic::OR,
+        "nor" => Mnemonic::NOR,
         "j" => Mnemonic::J,
+        "jal" => Mnemonic::JAL,
+        "jr" => Mnemonic::JR,
         "slt" => Mnemonic::SLT,
         "add" => Mnemonic::ADD,
         "sub" => Mnemonic::SUB,
         "addi" => Mnemonic::ADDI,
+        "andi" => Mnemonic::ANDI,
+        "xori" => Mnemonic::XORI,
         "beq" => Mnemonic::BEQ,
         "sw" => Mnemonic::SW,
         "lw" => Mnemonic::LW,
+        "bne" => Mnemonic::BNE,
+        "lui" => Mnemonic::LUI,
+        "ori" => Mnemonic::ORI,
+        "sll" => Mnemonic::SLL,
+        "srl" => Mnemonic::SRL,
+        "sra" => Mnemonic::SRA,
+        "move" => Mnemonic::MOVE,
+        "nop" => Mnemonic::NOP,
+        "li" => Mnemonic::LI,
+        "la" => Mnemonic::LA,
+        "blt" => Mnemonic::BLT,
+        "bgt" => Mnemonic::BGT,
         _ => {
-            return Err(format!("undefined mnemonic:{}", split_space[0]));
+            return Err(asm_error(
+                line,
+                &text,
+                mnemonic_str,
+                format!("undefined mnemonic `{}`", mnemonic_str),
+            ));
         }
     };
 
-    // println!("{:?} {:?}", label, mnemonic);
-
     let operands: Vec<Operand> = operands_str
         .iter()
-        .map(|&s| {
-            let cv: Vec<_> = s.chars().collect();
-            match cv[0] {
-                '$' => Operand::Reg(str2regidx(&s.to_string())),
-                '0'...'9' | '-' => Operand::Im(s.parse::<i32>().unwrap()),
-                _ => Operand::Label(s.to_string()),
-            }
-        })
-        .collect();
-    // println!("{:?}", operands);
+        .map(|&s| parse_operand(s, line, &text))
+        .collect::<Result<Vec<_>, _>>()?;
+
     Ok(Instruction {
         label: label,
         mnemonic: mnemonic,
         operands: operands,
+        line: line,
+        text: text,
     })
 }
 
-fn str2regidx(s: &String) -> u32 {
-    match s.as_ref() {
-        "$0" => 0,
-        "$at" => 1,
-        "$gp" => 28,
-        "$sp" => 29,
-        "$fp" => 30,
-        "$ra" => 31,
+fn parse_operand(s: &str, line: usize, text: &str) -> Result<Operand, AsmError> {
+    let cv: Vec<_> = s.chars().collect();
+    match cv[0] {
+        '$' => str2regidx(s).map(Operand::Reg).map_err(|message| {
+            asm_error(line, text, s, message)
+        }),
+        '0'...'9' | '-' => s.parse::<i32>().map(Operand::Im).map_err(|_| {
+            asm_error(line, text, s, format!("expected immediate, found `{}`", s))
+        }),
+        _ => Ok(Operand::Label(s.to_string())),
+    }
+}
+
+fn str2regidx(s: &str) -> Result<u32, String> {
+    match s {
+        "$0" => Ok(0),
+        "$at" => Ok(1),
+        "$gp" => Ok(28),
+        "$sp" => Ok(29),
+        "$fp" => Ok(30),
+        "$ra" => Ok(31),
         _ => {
             let cv: Vec<_> = s.chars().collect();
+            if cv.len() < 3 {
+                return Err(format!("illegal register `{}`", s));
+            }
             let prefix = cv[1];
-            let n = cv[2].to_digit(10).expect("illegal register");
+            let n = match cv[2].to_digit(10) {
+                Some(n) => n,
+                None => {
+                    return Err(format!("illegal register `{}`", s));
+                }
+            };
             match prefix {
-                'v' => n + 2,
-                'a' => n + 4,
-                't' => n + 8,
-                's' => if n < 8 { n + 16 } else { n + 24 },
-                'k' => n + 26,
-                _ => {
-                    panic!("illegal register");
+                'v' => Ok(n + 2),
+                'a' => Ok(n + 4),
+                't' => Ok(n + 8),
+                's' => Ok(if n < 8 { n + 16 } else { n + 24 }),
+                'k' => Ok(n + 26),
+                _ => Err(format!("illegal register `{}`", s)),
+            }
+        }
+    }
+}
+
+// Expands a single `macro NAME p1 p2 ... / ... / end` definition at each of
+// its call sites, so str2instr never has to know macros exist. Purely
+// textual, like the base assembler's own comma/paren handling. Expanded
+// lines are reported against the line number of their call site.
+fn expand_macros(lines: Vec<(usize, String)>) -> Vec<(usize, String)> {
+    let mut macros: HashMap<String, (Vec<String>, Vec<String>)> = HashMap::new();
+    let mut output: Vec<(usize, String)> = Vec::new();
+
+    let mut lines_iter = lines.into_iter();
+    while let Some((line_no, line)) = lines_iter.next() {
+        let trimmed = line.trim();
+        if trimmed.starts_with("macro ") {
+            let tokens: Vec<_> = trimmed.split_whitespace().collect();
+            let name = tokens[1].to_string();
+            let params: Vec<String> = tokens[2..]
+                .iter()
+                .map(|&s| s.trim_end_matches(',').to_string())
+                .collect();
+
+            let mut body: Vec<String> = Vec::new();
+            while let Some((_, body_line)) = lines_iter.next() {
+                if body_line.trim() == "end" {
+                    break;
+                }
+                body.push(body_line);
+            }
+            macros.insert(name, (params, body));
+            continue;
+        }
+
+        // A macro call can carry a label the same way an instruction does
+        // (`entry: push $t0`), so strip it off before looking up the
+        // mnemonic-position token, then reattach it to the expansion's
+        // first line.
+        let split_colon: Vec<_> = trimmed.splitn(2, ':').collect();
+        let (label, call_str) = if split_colon.len() == 2 {
+            (Some(split_colon[0].trim().to_string()), split_colon[1].trim())
+        } else {
+            (None, split_colon[0])
+        };
+
+        let tokens: Vec<_> = call_str.split_whitespace().collect();
+        let expanded = if !tokens.is_empty() {
+            macros.get(tokens[0]).map(|&(ref params, ref body)| {
+                let args: Vec<String> = tokens[1..]
+                    .join(" ")
+                    .split(',')
+                    .map(|s| s.trim().to_string())
+                    .filter(|s| s != "")
+                    .collect();
+                body.iter()
+                    .map(|body_line| {
+                        let mut expanded_line = body_line.clone();
+                        for (param, arg) in params.iter().zip(args.iter()) {
+                            expanded_line = replace_word(&expanded_line, param, arg);
+                        }
+                        expanded_line
+                    })
+                    .collect::<Vec<_>>()
+            })
+        } else {
+            None
+        };
+
+        match expanded {
+            Some(mut body_lines) => {
+                if let Some(label) = label {
+                    if let Some(first) = body_lines.first_mut() {
+                        *first = format!("{}: {}", label, first);
+                    }
                 }
+                output.extend(body_lines.into_iter().map(|l| (line_no, l)));
             }
+            None => output.push((line_no, line)),
         }
     }
+    output
 }
 
-fn mnemonic2funct(mnemonic: Mnemonic) -> u32 {
-    match mnemonic {
-        Mnemonic::ADD => 32,
-        Mnemonic::SUB => 34,
-        Mnemonic::AND => 36,
-        Mnemonic::OR => 37,
-        Mnemonic::SLT => 42,
-        _ => 0,
+// Splits on the same delimiters str2instr itself recognizes for operands
+// (commas, parens, whitespace), keeping the delimiters as their own
+// tokens so the line can be rejoined verbatim around a substitution.
+fn split_tokens(line: &str) -> Vec<String> {
+    let mut tokens = Vec::new();
+    let mut current = String::new();
+    for c in line.chars() {
+        if c == ',' || c == '(' || c == ')' || c.is_whitespace() {
+            if !current.is_empty() {
+                tokens.push(current.clone());
+                current.clear();
+            }
+            tokens.push(c.to_string());
+        } else {
+            current.push(c);
+        }
+    }
+    if !current.is_empty() {
+        tokens.push(current);
     }
+    tokens
 }
 
-fn mnemonictype(mnemonic: Mnemonic) -> InstructionType {
-    match mnemonic {
-        Mnemonic::ADD | Mnemonic::SUB | Mnemonic::AND | Mnemonic::OR | Mnemonic::SLT => {
-            InstructionType::R
+// Replaces whole-token occurrences of `word` in `line`. Tokenizes on
+// `,`/`(`/`)`/whitespace like str2instr does, so a parameter used inside a
+// memory operand such as `0(addr)` is still recognized as its own token
+// instead of being glued to the surrounding punctuation.
+fn replace_word(line: &str, word: &str, replacement: &str) -> String {
+    split_tokens(line)
+        .into_iter()
+        .map(|tok| if tok == word { replacement.to_string() } else { tok })
+        .collect::<Vec<_>>()
+        .join("")
+}
+
+// Rewrites pseudo-instructions into the real instructions they stand for.
+// Must run before items2bin builds its label map, since it changes the
+// instruction count (and therefore every later label's index).
+fn expand_pseudo(items: Vec<Item>) -> Result<Vec<Item>, Vec<AsmError>> {
+    let mut expanded = Vec::new();
+    let mut errors = Vec::new();
+
+    for item in items {
+        let instr = match item {
+            Item::Instr(instr) => instr,
+            other => {
+                expanded.push(other);
+                continue;
+            }
+        };
+        let label = instr.label.clone();
+        let line = instr.line;
+        let text = instr.text.clone();
+        match instr.mnemonic {
+            Mnemonic::MOVE => {
+                match &instr.operands[..] {
+                    [Operand::Reg(d), Operand::Reg(s)] => {
+                        expanded.push(Item::Instr(Instruction {
+                            label: label,
+                            mnemonic: Mnemonic::ADD,
+                            operands: vec![Operand::Reg(*d), Operand::Reg(REG_ZERO), Operand::Reg(*s)],
+                            line: line,
+                            text: text,
+                        }));
+                    }
+                    _ => errors.push(asm_error(line, &text, &text, "move requires 2 register operands".to_string())),
+                }
+            }
+            Mnemonic::NOP => {
+                expanded.push(Item::Instr(Instruction {
+                    label: label,
+                    mnemonic: Mnemonic::SLL,
+                    operands: vec![
+                        Operand::Reg(REG_ZERO),
+                        Operand::Reg(REG_ZERO),
+                        Operand::Im(0),
+                    ],
+                    line: line,
+                    text: text,
+                }));
+            }
+            Mnemonic::LI => {
+                match &instr.operands[..] {
+                    [Operand::Reg(d), Operand::Im(im)] => {
+                        if *im >= -32768 && *im <= 32767 {
+                            expanded.push(Item::Instr(Instruction {
+                                label: label,
+                                mnemonic: Mnemonic::ADDI,
+                                operands: vec![Operand::Reg(*d), Operand::Reg(REG_ZERO), Operand::Im(*im)],
+                                line: line,
+                                text: text,
+                            }));
+                        } else {
+                            expanded.push(Item::Instr(Instruction {
+                                label: label,
+                                mnemonic: Mnemonic::LUI,
+                                operands: vec![Operand::Reg(*d), Operand::Im((*im >> 16) & 0xffff)],
+                                line: line,
+                                text: text.clone(),
+                            }));
+                            expanded.push(Item::Instr(Instruction {
+                                label: None,
+                                mnemonic: Mnemonic::ORI,
+                                operands: vec![Operand::Reg(*d), Operand::Reg(*d), Operand::Im(*im & 0xffff)],
+                                line: line,
+                                text: text,
+                            }));
+                        }
+                    }
+                    _ => errors.push(asm_error(line, &text, &text, "li requires a register and an immediate".to_string())),
+                }
+            }
+            Mnemonic::LA => {
+                match &instr.operands[..] {
+                    [Operand::Reg(d), Operand::Label(target)] => {
+                        expanded.push(Item::Instr(Instruction {
+                            label: label,
+                            mnemonic: Mnemonic::LUI,
+                            operands: vec![Operand::Reg(*d), Operand::LabelHi(target.clone())],
+                            line: line,
+                            text: text.clone(),
+                        }));
+                        expanded.push(Item::Instr(Instruction {
+                            label: None,
+                            mnemonic: Mnemonic::ORI,
+                            operands: vec![Operand::Reg(*d), Operand::Reg(*d), Operand::LabelLo(target.clone())],
+                            line: line,
+                            text: text,
+                        }));
+                    }
+                    _ => errors.push(asm_error(line, &text, &text, "la requires a register and a label".to_string())),
+                }
+            }
+            Mnemonic::BLT | Mnemonic::BGT => {
+                match &instr.operands[..] {
+                    [Operand::Reg(s), Operand::Reg(t), Operand::Label(target)] => {
+                        let (slt_rs, slt_rt) = match instr.mnemonic {
+                            Mnemonic::BLT => (*s, *t),
+                            _ => (*t, *s),
+                        };
+                        expanded.push(Item::Instr(Instruction {
+                            label: label,
+                            mnemonic: Mnemonic::SLT,
+                            operands: vec![Operand::Reg(REG_AT), Operand::Reg(slt_rs), Operand::Reg(slt_rt)],
+                            line: line,
+                            text: text.clone(),
+                        }));
+                        expanded.push(Item::Instr(Instruction {
+                            label: None,
+                            mnemonic: Mnemonic::BNE,
+                            operands: vec![
+                                Operand::Reg(REG_AT),
+                                Operand::Reg(REG_ZERO),
+                                Operand::Label(target.clone()),
+                            ],
+                            line: line,
+                            text: text,
+                        }));
+                    }
+                    _ => errors.push(asm_error(line, &text, &text, "blt/bgt require 2 registers and a label".to_string())),
+                }
+            }
+            _ => expanded.push(Item::Instr(instr)),
         }
-        Mnemonic::ADDI | Mnemonic::BEQ | Mnemonic::LW | Mnemonic::SW => InstructionType::I,
-        Mnemonic::J => InstructionType::J,
     }
+
+    if errors.is_empty() { Ok(expanded) } else { Err(errors) }
+}
+
+// The operand shape str2instr produced for a mnemonic, i.e. which of the
+// parsed operands fill the rs/rt/rd/shamt/imm/target bit fields and in
+// what order. One descriptor per mnemonic replaces the separate
+// mnemonic2op/mnemonic2funct/mnemonictype tables and the permuted-tuple
+// matches the old per-type encoder used to pick them apart.
+#[derive(Clone, Copy)]
+enum Layout {
+    RRegs3,   // rd, rs, rt      add, sub, and, or, nor, slt
+    RShift,   // rd, rt, shamt   sll, srl, sra
+    RJump,    // rs              jr
+    IRegsImm, // rt, rs, imm     addi, andi, ori, xori
+    IMem,     // rt, imm(rs)     lw, sw
+    IBranch,  // rs, rt, label   beq, bne
+    IImm,     // rt, imm/label   lui
+    JTarget,  // label           j, jal
+}
+
+struct InstrDesc {
+    op: u32,
+    funct: u32,
+    layout: Layout,
 }
 
-fn mnemonic2op(mnemonic: Mnemonic) -> u32 {
+// The instruction table: each mnemonic's opcode/funct bits and operand
+// layout in one place instead of scattered match arms. It is a plain
+// `match` on the Mnemonic enum, not a perfect-hash map. Adding an
+// instruction takes one line here plus its Mnemonic variant, its spelling
+// in str2instr and mnemonic2str, and its decoding in bin2instr.
+fn instr_desc(mnemonic: Mnemonic) -> InstrDesc {
     match mnemonic {
-        Mnemonic::ADD | Mnemonic::SUB | Mnemonic::AND | Mnemonic::OR | Mnemonic::SLT => 0,
-        Mnemonic::ADDI => 8,
-        Mnemonic::LW => 35,
-        Mnemonic::SW => 43,
-        Mnemonic::BEQ => 4,
-        Mnemonic::J => 2,
+        Mnemonic::ADD => InstrDesc { op: 0, funct: 32, layout: Layout::RRegs3 },
+        Mnemonic::SUB => InstrDesc { op: 0, funct: 34, layout: Layout::RRegs3 },
+        Mnemonic::AND => InstrDesc { op: 0, funct: 36, layout: Layout::RRegs3 },
+        Mnemonic::OR => InstrDesc { op: 0, funct: 37, layout: Layout::RRegs3 },
+        Mnemonic::NOR => InstrDesc { op: 0, funct: 39, layout: Layout::RRegs3 },
+        Mnemonic::SLT => InstrDesc { op: 0, funct: 42, layout: Layout::RRegs3 },
+        Mnemonic::SLL => InstrDesc { op: 0, funct: 0, layout: Layout::RShift },
+        Mnemonic::SRL => InstrDesc { op: 0, funct: 2, layout: Layout::RShift },
+        Mnemonic::SRA => InstrDesc { op: 0, funct: 3, layout: Layout::RShift },
+        Mnemonic::JR => InstrDesc { op: 0, funct: 8, layout: Layout::RJump },
+        Mnemonic::ADDI => InstrDesc { op: 8, funct: 0, layout: Layout::IRegsImm },
+        Mnemonic::ANDI => InstrDesc { op: 12, funct: 0, layout: Layout::IRegsImm },
+        Mnemonic::ORI => InstrDesc { op: 13, funct: 0, layout: Layout::IRegsImm },
+        Mnemonic::XORI => InstrDesc { op: 14, funct: 0, layout: Layout::IRegsImm },
+        Mnemonic::LW => InstrDesc { op: 35, funct: 0, layout: Layout::IMem },
+        Mnemonic::SW => InstrDesc { op: 43, funct: 0, layout: Layout::IMem },
+        Mnemonic::BEQ => InstrDesc { op: 4, funct: 0, layout: Layout::IBranch },
+        Mnemonic::BNE => InstrDesc { op: 5, funct: 0, layout: Layout::IBranch },
+        Mnemonic::LUI => InstrDesc { op: 15, funct: 0, layout: Layout::IImm },
+        Mnemonic::J => InstrDesc { op: 2, funct: 0, layout: Layout::JTarget },
+        Mnemonic::JAL => InstrDesc { op: 3, funct: 0, layout: Layout::JTarget },
+        Mnemonic::MOVE | Mnemonic::NOP | Mnemonic::LI | Mnemonic::LA | Mnemonic::BLT |
+        Mnemonic::BGT => panic!("pseudo-instruction was not expanded before encoding"),
     }
 }
 
-fn instrs2bin(instrs: Vec<Instruction>) -> Vec<u32> {
-    let mut labelmap = HashMap::new();
-    for (i, instr) in instrs.iter().enumerate() {
-        if let Some(ref label) = instr.label {
-            labelmap.insert(label.clone(), i);
+fn resolve_label(label: &str, labelmap: &HashMap<String, u32>) -> Result<u32, String> {
+    labelmap.get(label).cloned().ok_or_else(|| {
+        format!("undefined label `{}`", label)
+    })
+}
+
+// Resolves an I-type immediate/label operand to its 16-bit field value.
+// LabelHi/LabelLo only ever appear once `la` has been expanded, and are
+// resolved against the final label map just like a branch target is.
+fn operand_imm16(operand: &Operand, labelmap: &HashMap<String, u32>) -> Result<u32, String> {
+    match operand {
+        &Operand::Im(im) => Ok(im as u32 & ((1 << 16) - 1)),
+        // A label used directly as an immediate (e.g. `lw $t0, arr($0)`)
+        // resolves to its address's low 16 bits, same as `la`'s %lo.
+        &Operand::Label(ref label) => {
+            resolve_label(label, labelmap).map(|adr| adr & ((1 << 16) - 1))
         }
+        &Operand::LabelHi(ref label) => {
+            resolve_label(label, labelmap).map(|adr| (adr >> 16) & ((1 << 16) - 1))
+        }
+        &Operand::LabelLo(ref label) => {
+            resolve_label(label, labelmap).map(|adr| adr & ((1 << 16) - 1))
+        }
+        &Operand::Reg(_) => Err("expected immediate, found register".to_string()),
     }
+}
+
+// Fills the bit fields an instruction's descriptor calls for from its
+// parsed operands. Each `Layout` arm knows the operand count and order
+// str2instr produced for that shape; the encoding itself is then the same
+// `op << 26 | ...` assembly regardless of which mnemonic got us here.
+fn encode(instr: &Instruction, desc: &InstrDesc, address: u32, labelmap: &HashMap<String, u32>, errors: &mut Vec<AsmError>) -> Option<u32> {
+    let op = desc.op;
+    let funct = desc.funct;
+    let operands = &instr.operands;
 
-    let mut instrs_bin: Vec<u32> = Vec::new();
-    for (i, instr) in instrs.iter().enumerate() {
-        let bin = match mnemonictype(instr.mnemonic) {
-            InstructionType::R => {
-                let op = mnemonic2op(instr.mnemonic);
-                let operands = &instr.operands;
-                if operands.len() != 3 {
-                    panic!("something wrong!");
+    let arity_error = |errors: &mut Vec<AsmError>, expected: usize| {
+        errors.push(asm_error(
+            instr.line,
+            &instr.text,
+            &instr.text,
+            format!("expected {} operand(s), found {}", expected, operands.len()),
+        ));
+    };
+
+    match desc.layout {
+        Layout::RRegs3 => {
+            if operands.len() != 3 {
+                arity_error(errors, 3);
+                return None;
+            }
+            match (&operands[0], &operands[1], &operands[2]) {
+                (&Operand::Reg(rd), &Operand::Reg(rs), &Operand::Reg(rt)) => {
+                    Some(op << 26 | rs << 21 | rt << 16 | rd << 11 | funct)
                 }
-                let operands = match (&operands[1], &operands[2], &operands[0]) {
-                    (&Operand::Reg(rs), &Operand::Reg(rt), &Operand::Reg(rd)) => {
-                        rs << 21 | rt << 16 | rd << 11
-                    }
-                    _ => {
-                        panic!("something wrong!");
-                    }
-                };
-                let funct = mnemonic2funct(instr.mnemonic);
-                op << 26 | operands | funct
-            }
-            InstructionType::I => {
-                let op = mnemonic2op(instr.mnemonic);
-                let operands = &instr.operands;
-                if operands.len() != 3 {
-                    panic!("something wrong!");
+                _ => {
+                    errors.push(asm_error(instr.line, &instr.text, &instr.text, "expected rd, rs, rt".to_string()));
+                    None
                 }
-                let operands = match (&operands[1], &operands[0], &operands[2]) {
-                    (&Operand::Reg(rs), &Operand::Reg(rt), &Operand::Im(im)) => {
-                        rs << 21 | rt << 16 | (im as u32 & ((1 << 16) - 1))
-                    }
-                    (&Operand::Im(im), &Operand::Reg(rt), &Operand::Reg(rs)) => {
-                        rs << 21 | rt << 16 | (im as u32 & ((1 << 16) - 1))
+            }
+        }
+        Layout::RShift => {
+            if operands.len() != 3 {
+                arity_error(errors, 3);
+                return None;
+            }
+            match (&operands[0], &operands[1], &operands[2]) {
+                (&Operand::Reg(rd), &Operand::Reg(rt), &Operand::Im(shamt)) => {
+                    Some(op << 26 | rt << 16 | rd << 11 | (shamt as u32 & 0x1f) << 6 | funct)
+                }
+                _ => {
+                    errors.push(asm_error(instr.line, &instr.text, &instr.text, "expected rd, rt, shamt".to_string()));
+                    None
+                }
+            }
+        }
+        Layout::RJump => {
+            if operands.len() != 1 {
+                arity_error(errors, 1);
+                return None;
+            }
+            match &operands[0] {
+                &Operand::Reg(rs) => Some(op << 26 | rs << 21 | funct),
+                _ => {
+                    errors.push(asm_error(instr.line, &instr.text, &instr.text, "expected register".to_string()));
+                    None
+                }
+            }
+        }
+        Layout::IRegsImm => {
+            if operands.len() != 3 {
+                arity_error(errors, 3);
+                return None;
+            }
+            match (&operands[0], &operands[1]) {
+                (&Operand::Reg(rt), &Operand::Reg(rs)) => {
+                    match operand_imm16(&operands[2], labelmap) {
+                        Ok(imm) => Some(op << 26 | rs << 21 | rt << 16 | imm),
+                        Err(message) => {
+                            errors.push(asm_error(instr.line, &instr.text, &instr.text, message));
+                            None
+                        }
                     }
-                    (&Operand::Reg(rt), &Operand::Reg(rs), &Operand::Label(ref label)) => {
-                        if let Some(adr) = labelmap.get(label) {
-                            rs << 21 | rt << 16 | ((adr - 1 - i) as u32 & ((1 << 16) - 1))
-                        } else {
-                            panic!("something wrong!");
+                }
+                _ => {
+                    errors.push(asm_error(instr.line, &instr.text, &instr.text, "expected rt, rs, immediate".to_string()));
+                    None
+                }
+            }
+        }
+        Layout::IMem => {
+            if operands.len() != 3 {
+                arity_error(errors, 3);
+                return None;
+            }
+            match (&operands[0], &operands[2]) {
+                (&Operand::Reg(rt), &Operand::Reg(rs)) => {
+                    match operand_imm16(&operands[1], labelmap) {
+                        Ok(imm) => Some(op << 26 | rs << 21 | rt << 16 | imm),
+                        Err(message) => {
+                            errors.push(asm_error(instr.line, &instr.text, &instr.text, message));
+                            None
                         }
                     }
-                    _ => {
-                        panic!("something wrong!");
+                }
+                _ => {
+                    errors.push(asm_error(instr.line, &instr.text, &instr.text, "expected rt, immediate(rs)".to_string()));
+                    None
+                }
+            }
+        }
+        Layout::IBranch => {
+            if operands.len() != 3 {
+                arity_error(errors, 3);
+                return None;
+            }
+            match (&operands[0], &operands[1], &operands[2]) {
+                (&Operand::Reg(rs), &Operand::Reg(rt), &Operand::Label(ref label)) => {
+                    match resolve_label(label, labelmap) {
+                        Ok(adr) => Some(
+                            op << 26 | rs << 21 | rt << 16 |
+                                ((adr as i64 - 1 - address as i64) as u32 & ((1 << 16) - 1)),
+                        ),
+                        Err(message) => {
+                            errors.push(asm_error(instr.line, &instr.text, label, message));
+                            None
+                        }
                     }
-                };
-                op << 26 | operands
+                }
+                _ => {
+                    errors.push(asm_error(instr.line, &instr.text, &instr.text, "expected rs, rt, label".to_string()));
+                    None
+                }
             }
-            InstructionType::J => {
-                let op = mnemonic2op(instr.mnemonic);
-                let operands = &instr.operands;
-                if operands.len() != 1 {
-                    panic!("something wrong!");
+        }
+        Layout::IImm => {
+            if operands.len() != 2 {
+                arity_error(errors, 2);
+                return None;
+            }
+            let rt = match &operands[0] {
+                &Operand::Reg(rt) => rt,
+                _ => {
+                    errors.push(asm_error(instr.line, &instr.text, &instr.text, "expected register, found immediate".to_string()));
+                    return None;
                 }
-                let operands = match &operands[0] {
-                    &Operand::Label(ref label) => {
-                        if let Some(&pos) = labelmap.get(label) {
-                            pos as u32
-                        } else {
-                            panic!("something wrong!");
+            };
+            match operand_imm16(&operands[1], labelmap) {
+                Ok(imm) => Some(op << 26 | rt << 16 | imm),
+                Err(message) => {
+                    errors.push(asm_error(instr.line, &instr.text, &instr.text, message));
+                    None
+                }
+            }
+        }
+        Layout::JTarget => {
+            if operands.len() != 1 {
+                arity_error(errors, 1);
+                return None;
+            }
+            match &operands[0] {
+                &Operand::Label(ref label) => {
+                    match resolve_label(label, labelmap) {
+                        Ok(adr) => Some(op << 26 | adr),
+                        Err(message) => {
+                            errors.push(asm_error(instr.line, &instr.text, label, message));
+                            None
                         }
                     }
-                    _ => {
-                        panic!("something wrong!");
-                    }
-                };
-                op << 26 | operands
+                }
+                _ => {
+                    errors.push(asm_error(instr.line, &instr.text, &instr.text, "expected label".to_string()));
+                    None
+                }
             }
-        };
-        instrs_bin.push(bin);
+        }
+    }
+}
+
+// Computes each label's word address in one pass over the item stream.
+// `.org` jumps the address forward (or back) directly; everything else
+// (instructions, `.word`, `.space`) just advances it by however many words
+// it occupies.
+fn resolve_addresses(items: &[Item]) -> HashMap<String, u32> {
+    let mut labelmap = HashMap::new();
+    let mut address: u32 = 0;
+    for item in items {
+        match item {
+            &Item::Instr(ref instr) => {
+                if let Some(ref label) = instr.label {
+                    labelmap.insert(label.clone(), address);
+                }
+                address += 1;
+            }
+            &Item::Word { ref label, ref values } => {
+                if let Some(ref label) = *label {
+                    labelmap.insert(label.clone(), address);
+                }
+                address += values.len() as u32;
+            }
+            &Item::Space { ref label, count } => {
+                if let Some(ref label) = *label {
+                    labelmap.insert(label.clone(), address);
+                }
+                address += count as u32;
+            }
+            &Item::Org(target) => address = target,
+            &Item::Segment => {}
+        }
+    }
+    labelmap
+}
+
+// Zero-fills `words` up to `address`, the way `.org`/`.space` leave holes
+// in the output stream for memory the source never explicitly writes.
+fn pad_to(words: &mut Vec<u32>, address: u32) {
+    while (words.len() as u32) < address {
+        words.push(0);
+    }
+}
+
+// Writes `value` at word `address`, extending `words` first if needed.
+// Writes in place rather than appending so a `.org` that moves the address
+// backward overwrites the right word instead of silently appending past
+// the end of the stream.
+fn write_word(words: &mut Vec<u32>, address: u32, value: u32) {
+    pad_to(words, address + 1);
+    words[address as usize] = value;
+}
+
+fn items2bin(items: Vec<Item>) -> Result<Vec<u32>, Vec<AsmError>> {
+    let labelmap = resolve_addresses(&items);
+
+    let mut words: Vec<u32> = Vec::new();
+    let mut errors: Vec<AsmError> = Vec::new();
+    let mut address: u32 = 0;
+    for item in items {
+        match item {
+            Item::Instr(instr) => {
+                let desc = instr_desc(instr.mnemonic);
+                let encoded = encode(&instr, &desc, address, &labelmap, &mut errors);
+                if let Some(bin) = encoded {
+                    write_word(&mut words, address, bin);
+                }
+                address += 1;
+            }
+            Item::Word { values, .. } => {
+                for (offset, value) in values.iter().enumerate() {
+                    write_word(&mut words, address + offset as u32, *value as u32);
+                }
+                address += values.len() as u32;
+            }
+            Item::Space { count, .. } => {
+                address += count as u32;
+            }
+            Item::Org(target) => address = target,
+            Item::Segment => {}
+        }
+    }
+
+    if errors.is_empty() { Ok(words) } else { Err(errors) }
+}
+
+fn regidx2str(idx: u32) -> String {
+    match idx {
+        0 => "$0".to_string(),
+        1 => "$at".to_string(),
+        28 => "$gp".to_string(),
+        29 => "$sp".to_string(),
+        30 => "$fp".to_string(),
+        31 => "$ra".to_string(),
+        2...3 => format!("$v{}", idx - 2),
+        4...7 => format!("$a{}", idx - 4),
+        8...15 => format!("$t{}", idx - 8),
+        16...23 => format!("$s{}", idx - 16),
+        26...27 => format!("$k{}", idx - 26),
+        _ => format!("${}", idx),
+    }
+}
+
+fn mnemonic2str(mnemonic: Mnemonic) -> &'static str {
+    match mnemonic {
+        Mnemonic::AND => "and",
+        Mnemonic::OR => "or",
+        Mnemonic::NOR => "nor",
+        Mnemonic::J => "j",
+        Mnemonic::JAL => "jal",
+        Mnemonic::JR => "jr",
+        Mnemonic::SLT => "slt",
+        Mnemonic::ADD => "add",
+        Mnemonic::SUB => "sub",
+        Mnemonic::ADDI => "addi",
+        Mnemonic::ANDI => "andi",
+        Mnemonic::XORI => "xori",
+        Mnemonic::BEQ => "beq",
+        Mnemonic::SW => "sw",
+        Mnemonic::LW => "lw",
+        Mnemonic::BNE => "bne",
+        Mnemonic::LUI => "lui",
+        Mnemonic::ORI => "ori",
+        Mnemonic::SLL => "sll",
+        Mnemonic::SRL => "srl",
+        Mnemonic::SRA => "sra",
+        Mnemonic::MOVE | Mnemonic::NOP | Mnemonic::LI | Mnemonic::LA | Mnemonic::BLT |
+        Mnemonic::BGT => panic!("pseudo-instruction has no mnemonic text"),
+    }
+}
+
+fn operand2str(operand: &Operand) -> String {
+    match operand {
+        &Operand::Reg(r) => regidx2str(r),
+        &Operand::Im(im) => format!("{}", im),
+        &Operand::Label(ref label) => label.clone(),
+        &Operand::LabelHi(ref label) => format!("%hi({})", label),
+        &Operand::LabelLo(ref label) => format!("%lo({})", label),
+    }
+}
+
+fn instr2str(instr: &Instruction) -> String {
+    let mnemonic_str = mnemonic2str(instr.mnemonic);
+    let body = match instr.mnemonic {
+        Mnemonic::LW | Mnemonic::SW => format!(
+            "{} {}, {}({})",
+            mnemonic_str,
+            operand2str(&instr.operands[0]),
+            operand2str(&instr.operands[1]),
+            operand2str(&instr.operands[2])
+        ),
+        _ => {
+            let operand_strs: Vec<String> = instr.operands.iter().map(operand2str).collect();
+            format!("{} {}", mnemonic_str, operand_strs.join(", "))
+        }
+    };
+    match instr.label {
+        Some(ref label) => format!("{}: {}", label, body),
+        None => body,
+    }
+}
+
+// Decodes machine words back into Instructions, the reverse of items2bin.
+// Branch/jump targets come back as absolute word indices, which are then
+// turned into synthesized `L0`, `L1`, ... labels so the disassembly can be
+// re-assembled and round-trip to the same binary. Registers 24 and 25 print
+// as `$24`/`$25`, which str2regidx does not accept, so code using them does
+// not re-assemble.
+fn bin2instr(words: &[u32]) -> Vec<Instruction> {
+    struct Decoded {
+        mnemonic: Mnemonic,
+        regs: Vec<Operand>,
+        target: Option<usize>,
+    }
+
+    let mut decoded: Vec<Decoded> = Vec::with_capacity(words.len());
+    for (i, &word) in words.iter().enumerate() {
+        let opcode = (word >> 26) & 0x3f;
+        if opcode == 0 {
+            let funct = word & 0x3f;
+            let rs = (word >> 21) & 0x1f;
+            let rt = (word >> 16) & 0x1f;
+            let rd = (word >> 11) & 0x1f;
+            let shamt = (word >> 6) & 0x1f;
+            let mnemonic = match funct {
+                32 => Mnemonic::ADD,
+                34 => Mnemonic::SUB,
+                36 => Mnemonic::AND,
+                37 => Mnemonic::OR,
+                39 => Mnemonic::NOR,
+                42 => Mnemonic::SLT,
+                0 => Mnemonic::SLL,
+                2 => Mnemonic::SRL,
+                3 => Mnemonic::SRA,
+                8 => Mnemonic::JR,
+                _ => panic!("unknown funct {} at word {}", funct, i),
+            };
+            let regs = match mnemonic {
+                Mnemonic::SLL | Mnemonic::SRL | Mnemonic::SRA => {
+                    vec![Operand::Reg(rd), Operand::Reg(rt), Operand::Im(shamt as i32)]
+                }
+                Mnemonic::JR => vec![Operand::Reg(rs)],
+                _ => vec![Operand::Reg(rd), Operand::Reg(rs), Operand::Reg(rt)],
+            };
+            decoded.push(Decoded { mnemonic: mnemonic, regs: regs, target: None });
+        } else {
+            let rs = (word >> 21) & 0x1f;
+            let rt = (word >> 16) & 0x1f;
+            let imm16 = (word & 0xffff) as i16 as i32;
+            match opcode {
+                8 => decoded.push(Decoded {
+                    mnemonic: Mnemonic::ADDI,
+                    regs: vec![Operand::Reg(rt), Operand::Reg(rs), Operand::Im(imm16)],
+                    target: None,
+                }),
+                35 => decoded.push(Decoded {
+                    mnemonic: Mnemonic::LW,
+                    regs: vec![Operand::Reg(rt), Operand::Im(imm16), Operand::Reg(rs)],
+                    target: None,
+                }),
+                43 => decoded.push(Decoded {
+                    mnemonic: Mnemonic::SW,
+                    regs: vec![Operand::Reg(rt), Operand::Im(imm16), Operand::Reg(rs)],
+                    target: None,
+                }),
+                4 => decoded.push(Decoded {
+                    mnemonic: Mnemonic::BEQ,
+                    regs: vec![Operand::Reg(rs), Operand::Reg(rt)],
+                    target: Some((i as i64 + 1 + imm16 as i64) as usize),
+                }),
+                5 => decoded.push(Decoded {
+                    mnemonic: Mnemonic::BNE,
+                    regs: vec![Operand::Reg(rs), Operand::Reg(rt)],
+                    target: Some((i as i64 + 1 + imm16 as i64) as usize),
+                }),
+                15 => decoded.push(Decoded {
+                    mnemonic: Mnemonic::LUI,
+                    regs: vec![Operand::Reg(rt), Operand::Im((word & 0xffff) as i32)],
+                    target: None,
+                }),
+                13 => decoded.push(Decoded {
+                    mnemonic: Mnemonic::ORI,
+                    regs: vec![Operand::Reg(rt), Operand::Reg(rs), Operand::Im((word & 0xffff) as i32)],
+                    target: None,
+                }),
+                12 => decoded.push(Decoded {
+                    mnemonic: Mnemonic::ANDI,
+                    regs: vec![Operand::Reg(rt), Operand::Reg(rs), Operand::Im((word & 0xffff) as i32)],
+                    target: None,
+                }),
+                14 => decoded.push(Decoded {
+                    mnemonic: Mnemonic::XORI,
+                    regs: vec![Operand::Reg(rt), Operand::Reg(rs), Operand::Im((word & 0xffff) as i32)],
+                    target: None,
+                }),
+                2 => decoded.push(Decoded {
+                    mnemonic: Mnemonic::J,
+                    regs: vec![],
+                    target: Some((word & 0x3ffffff) as usize),
+                }),
+                3 => decoded.push(Decoded {
+                    mnemonic: Mnemonic::JAL,
+                    regs: vec![],
+                    target: Some((word & 0x3ffffff) as usize),
+                }),
+                _ => panic!("unknown opcode {} at word {}", opcode, i),
+            }
+        }
+    }
+
+    let mut targets: Vec<usize> = decoded.iter().filter_map(|d| d.target).collect();
+    targets.sort();
+    targets.dedup();
+    let label_name = |idx: usize| format!("L{}", targets.iter().position(|&t| t == idx).unwrap());
+
+    decoded
+        .into_iter()
+        .enumerate()
+        .map(|(i, d)| {
+            let mut operands = d.regs;
+            if let Some(target) = d.target {
+                operands.push(Operand::Label(label_name(target)));
+            }
+            Instruction {
+                label: if targets.contains(&i) { Some(label_name(i)) } else { None },
+                mnemonic: d.mnemonic,
+                operands: operands,
+                line: i + 1,
+                text: String::new(),
+            }
+        })
+        .collect()
+}
+
+#[derive(Clone, Copy)]
+enum Endian {
+    Little,
+    Big,
+}
+
+// An output backend turns the assembled words into a particular file
+// format. Picked by the `--format` flag and driven directly (not through a
+// trait object) since exactly one backend runs per invocation.
+trait EmitFormat {
+    fn emit(&self, words: &[u32], out: &mut impl Write);
+}
+
+// The assembler's original format: one zero-padded 8-hex-digit word per
+// line, padded with zero words up to `words`.
+struct HexFormat {
+    words: usize,
+}
+
+impl EmitFormat for HexFormat {
+    fn emit(&self, words: &[u32], out: &mut impl Write) {
+        for i in 0..self.words.max(words.len()) {
+            let word = words.get(i).cloned().unwrap_or(0);
+            writeln!(out, "{:08x}", word).expect("error: could not write output");
+        }
+    }
+}
+
+// A Verilog `$readmemh`-compatible memory image, with an optional leading
+// `@address` directive.
+struct VerilogReadmemh {
+    words: usize,
+    address: Option<u32>,
+}
+
+impl EmitFormat for VerilogReadmemh {
+    fn emit(&self, words: &[u32], out: &mut impl Write) {
+        if let Some(address) = self.address {
+            writeln!(out, "@{:08x}", address).expect("error: could not write output");
+        }
+        for i in 0..self.words.max(words.len()) {
+            let word = words.get(i).cloned().unwrap_or(0);
+            writeln!(out, "{:08x}", word).expect("error: could not write output");
+        }
+    }
+}
+
+// Logisim's "v2.0 raw" memory image format.
+struct LogisimImage {
+    words: usize,
+}
+
+impl EmitFormat for LogisimImage {
+    fn emit(&self, words: &[u32], out: &mut impl Write) {
+        writeln!(out, "v2.0 raw").expect("error: could not write output");
+        for i in 0..self.words.max(words.len()) {
+            let word = words.get(i).cloned().unwrap_or(0);
+            writeln!(out, "{:x}", word).expect("error: could not write output");
+        }
+    }
+}
+
+// A raw byte dump suitable for loading straight into a simulator's memory.
+struct RawBinary {
+    words: usize,
+    endian: Endian,
+}
+
+impl EmitFormat for RawBinary {
+    fn emit(&self, words: &[u32], out: &mut impl Write) {
+        for i in 0..self.words.max(words.len()) {
+            let word = words.get(i).cloned().unwrap_or(0);
+            let bytes = match self.endian {
+                Endian::Little => word.to_le_bytes(),
+                Endian::Big => word.to_be_bytes(),
+            };
+            out.write_all(&bytes).expect("error: could not write output");
+        }
+    }
+}
+
+fn disassemble(filename: &str) {
+    let file = match File::open(filename) {
+        Ok(file) => file,
+        Err(e) => {
+            panic!("{}: {}", filename, e);
+        }
+    };
+    let buf_file = BufReader::new(file);
+
+    let words: Vec<u32> = buf_file
+        .lines()
+        .map(|line| line.expect("error: could not read line"))
+        .map(|line| line.trim().to_string())
+        .filter(|line| line != "")
+        .map(|line| {
+            u32::from_str_radix(&line, 16).expect("error: expected an 8-hex-digit word")
+        })
+        .collect();
+
+    for instr in bin2instr(&words) {
+        println!("{}", instr2str(&instr));
     }
-    instrs_bin
 }
 
 fn main() {
     let args: Vec<String> = env::args().collect();
-    if args.len() < 2 {
-        println!("{} [file]", args[0]);
+
+    let mut disassemble_mode = false;
+    let mut format = "hex".to_string();
+    let mut words = 64;
+    let mut address: Option<u32> = None;
+    let mut endian = Endian::Little;
+    let mut filename: Option<String> = None;
+
+    let mut i = 1;
+    while i < args.len() {
+        match args[i].as_str() {
+            "-d" => disassemble_mode = true,
+            "--format" => {
+                i += 1;
+                format = args[i].clone();
+            }
+            "--words" => {
+                i += 1;
+                words = args[i].parse().expect("--words expects a number");
+            }
+            "--address" => {
+                i += 1;
+                address = Some(u32::from_str_radix(
+                    args[i].trim_start_matches("0x"),
+                    16,
+                ).expect("--address expects a hex address"));
+            }
+            "--endian" => {
+                i += 1;
+                endian = match args[i].as_str() {
+                    "little" => Endian::Little,
+                    "big" => Endian::Big,
+                    _ => panic!("--endian expects \"little\" or \"big\""),
+                };
+            }
+            _ => filename = Some(args[i].clone()),
+        }
+        i += 1;
+    }
+
+    let filename = match filename {
+        Some(filename) => filename,
+        None => {
+            println!(
+                "{} [--format hex|verilog|logisim|bin] [--words N] [--address ADDR] [--endian little|big] [-d] [file]",
+                args[0]
+            );
+            return;
+        }
+    };
+
+    if disassemble_mode {
+        disassemble(&filename);
         return;
     }
-    let filename = args[1].clone();
     let file = match File::open(&filename) {
         Ok(file) => file,
         Err(e) => {
@@ -244,14 +1238,15 @@ fn main() {
     };
     let mut buf_file = BufReader::new(file);
 
-    let mut buffer = String::new();
-    let mut program: Vec<Instruction> = Vec::new();
+    let mut lines: Vec<(usize, String)> = Vec::new();
+    let mut line_no = 0;
     loop {
+        let mut buffer = String::new();
         match buf_file.read_line(&mut buffer) {
             Ok(0) => break,
             Ok(_) => {
-                program.push(str2instr(&buffer).expect("error:"));
-                buffer.clear();
+                line_no += 1;
+                lines.push((line_no, buffer));
             }
             Err(e) => {
                 println!("{}", e);
@@ -259,11 +1254,54 @@ fn main() {
             }
         }
     }
-    let instrs_bin = instrs2bin(program);
-    for instr_bin in &instrs_bin {
-        println!("{:08x}", instr_bin);
+
+    let lines = expand_macros(lines);
+
+    let mut program: Vec<Item> = Vec::new();
+    let mut errors: Vec<AsmError> = Vec::new();
+    for (line_no, line) in &lines {
+        if line.trim().is_empty() {
+            continue;
+        }
+        match str2item(line, *line_no) {
+            Ok(item) => program.push(item),
+            Err(e) => errors.push(e),
+        }
     }
-    for _ in 0..(64 - instrs_bin.len()) {
-        println!("{:08x}", 0 as u32);
+    // Keep going through the later stages on whatever parsed successfully
+    // so that a bad mnemonic on one line and a bad operand/undefined label
+    // on another are reported together instead of one run at a time.
+    // If any pseudo-instruction fails to expand, encoding is skipped, so
+    // the encoder's errors (undefined labels, bad operands) are not reported
+    // in that run.
+    let program = match expand_pseudo(program) {
+        Ok(program) => program,
+        Err(mut e) => {
+            errors.append(&mut e);
+            Vec::new()
+        }
+    };
+
+    let instrs_bin = match items2bin(program) {
+        Ok(instrs_bin) => instrs_bin,
+        Err(mut e) => {
+            errors.append(&mut e);
+            Vec::new()
+        }
+    };
+
+    if !errors.is_empty() {
+        report_errors(&filename, &errors);
+        process::exit(1);
+    }
+
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+    match format.as_str() {
+        "hex" => HexFormat { words: words }.emit(&instrs_bin, &mut out),
+        "verilog" => VerilogReadmemh { words: words, address: address }.emit(&instrs_bin, &mut out),
+        "logisim" => LogisimImage { words: words }.emit(&instrs_bin, &mut out),
+        "bin" => RawBinary { words: words, endian: endian }.emit(&instrs_bin, &mut out),
+        _ => panic!("unknown --format {}", format),
     }
 }